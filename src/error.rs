@@ -0,0 +1,77 @@
+use orfail::Failure;
+
+/// Error returned by [`ChatGpt::call()`](crate::ChatGpt::call), categorized so that callers can tell failures apart.
+#[derive(Debug)]
+pub enum DaberuError {
+    /// The API rejected the credentials (HTTP 401 or 403).
+    Auth { status: u16, body: String },
+
+    /// The API rate limit was exceeded (HTTP 429).
+    RateLimited { body: String },
+
+    /// The API server is overloaded or temporarily unavailable (HTTP 503 or 529).
+    Overloaded { status: u16, body: String },
+
+    /// The API returned another unsuccessful HTTP status.
+    Http { status: u16, body: String },
+
+    /// The API response could not be parsed.
+    Parse { message: String },
+
+    /// The request timed out.
+    Timeout { message: String },
+
+    /// The command-line arguments or input are invalid (detected after argument parsing).
+    Usage { message: String },
+
+    /// The user interrupted the streamed reply with Ctrl-C.
+    Interrupted,
+
+    /// Any other failure.
+    Other(Failure),
+}
+
+impl DaberuError {
+    pub fn usage(message: impl Into<String>) -> Self {
+        Self::Usage {
+            message: message.into(),
+        }
+    }
+
+    pub fn from_status(status: u16, body: String) -> Self {
+        match status {
+            401 | 403 => Self::Auth { status, body },
+            429 => Self::RateLimited { body },
+            503 | 529 => Self::Overloaded { status, body },
+            _ => Self::Http { status, body },
+        }
+    }
+}
+
+impl std::fmt::Display for DaberuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Auth { status, body } => {
+                write!(f, "authentication failed (HTTP {status}): {body}")
+            }
+            Self::RateLimited { body } => write!(f, "rate limit exceeded (HTTP 429): {body}"),
+            Self::Overloaded { status, body } => {
+                write!(f, "API server overloaded (HTTP {status}): {body}")
+            }
+            Self::Http { status, body } => write!(f, "API request failed (HTTP {status}): {body}"),
+            Self::Parse { message } => write!(f, "failed to parse API response: {message}"),
+            Self::Timeout { message } => write!(f, "{message}"),
+            Self::Usage { message } => write!(f, "{message}"),
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::Other(failure) => write!(f, "{failure}"),
+        }
+    }
+}
+
+impl std::error::Error for DaberuError {}
+
+impl From<Failure> for DaberuError {
+    fn from(failure: Failure) -> Self {
+        Self::Other(failure)
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// State shared between a Ctrl-C handler and [`ChatGpt::call()`](crate::ChatGpt::call).
+///
+/// While a reply is being streamed, an interrupt request stops the stream, the reply received so far is
+/// saved to `--log`, and the call returns [`DaberuError::Interrupted`](crate::DaberuError::Interrupted).
+#[derive(Debug, Default)]
+pub struct Interrupt {
+    streaming: AtomicBool,
+    requested: AtomicBool,
+}
+
+impl Interrupt {
+    /// Requests an interrupt.
+    ///
+    /// Returns `false` if no reply is being streamed or an interrupt has already been requested,
+    /// in which case the caller should abort by itself (e.g., exit the process).
+    pub fn request(&self) -> bool {
+        self.streaming.load(Ordering::SeqCst) && !self.requested.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Marks a reply as being streamed until the returned guard is dropped.
+    pub(crate) fn start_streaming(&self) -> StreamingGuard<'_> {
+        self.requested.store(false, Ordering::SeqCst);
+        self.streaming.store(true, Ordering::SeqCst);
+        StreamingGuard(self)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StreamingGuard<'a>(&'a Interrupt);
+
+impl Drop for StreamingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.streaming.store(false, Ordering::SeqCst);
+    }
+}
@@ -1,9 +1,24 @@
+mod error;
+mod interrupt;
+mod spinner;
+
+pub use error::DaberuError;
+pub use interrupt::Interrupt;
+
 use orfail::{Failure, OrFail};
+use spinner::Spinner;
 use std::{
-    io::{BufRead, BufReader, Read, Write},
-    path::PathBuf,
+    io::{BufRead, BufReader, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc::RecvTimeoutError, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+const DEFAULT_MODEL: &str = "gpt-4o";
+
+/// Upper bound of the delay between retries, whether it comes from `retry-after` or the exponential backoff.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+
 #[derive(Debug, clap::Args)]
 pub struct ChatGpt {
     /// OpenAI API key.
@@ -13,83 +28,761 @@ pub struct ChatGpt {
         env = "OPENAI_API_KEY",
         hide_env_values = true
     )]
-    api_key: String,
+    api_key: Option<String>,
+
+    /// Base URL of the OpenAI-compatible API (e.g., a local server or an Azure OpenAI deployment URL).
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "OPENAI_BASE_URL",
+        default_value = "https://api.openai.com/v1",
+        value_parser = parse_base_url
+    )]
+    openai_base_url: String,
+
+    /// If specified, the API is called in Azure OpenAI style: the version is added as the `api-version` query parameter
+    /// and the API key is sent in the `api-key` header. `--openai-base-url` should then be the deployment URL
+    /// (e.g., `https://RESOURCE.openai.azure.com/openai/deployments/DEPLOYMENT`).
+    #[arg(long, value_name = "VERSION", env = "AZURE_OPENAI_API_VERSION")]
+    azure_api_version: Option<String>,
 
     /// Log file path to save the conversation history. If the file already exists, the history will be considered in the next conversation.
     #[arg(long, value_name = "LOG_FILE_PATH")]
     log: Option<PathBuf>,
 
-    /// ChatGPT model name.
-    #[arg(long, env = "CHATGPT_MODEL", default_value = "gpt-4o")]
-    model: String,
+    /// If specified, new messages are appended to the `--log` file, but the past conversation in it is not sent to the API
+    /// (only its system message is).
+    #[arg(long, requires = "log", conflicts_with = "log_stdin")]
+    append: bool,
+
+    /// If specified, the conversation history (a JSON array of messages) is read from stdin instead of your message.
+    /// The message is given by `MESSAGE` or `--message` and the updated history is written to stdout (or to `--log` if specified).
+    #[arg(long)]
+    log_stdin: bool,
+
+    /// Message to send. If neither this nor `--message` is specified, the message is read from stdin.
+    #[arg(value_name = "MESSAGE")]
+    message: Option<String>,
+
+    /// Message to send instead of reading it from stdin (the positional `MESSAGE` takes precedence).
+    #[arg(
+        short = 'M',
+        long = "message",
+        value_name = "MESSAGE",
+        alias = "prompt"
+    )]
+    message_option: Option<String>,
+
+    /// ChatGPT model name [default: gpt-4o].
+    #[arg(long, env = "CHATGPT_MODEL")]
+    model: Option<String>,
+
+    /// If specified, the model that wrote the last reply in the conversation history is used,
+    /// unless `--model` (or `CHATGPT_MODEL`) is given.
+    #[arg(long)]
+    model_from_log: bool,
+
+    /// Comma-separated list of known model names. If specified, `--model` is checked against it before calling the API.
+    #[arg(
+        long,
+        value_name = "MODELS",
+        env = "CHATGPT_KNOWN_MODELS",
+        value_delimiter = ','
+    )]
+    known_models: Vec<String>,
+
+    /// If specified, a model not in `--known-models` is an error rather than a warning.
+    #[arg(long)]
+    strict_model: bool,
+
+    /// Model alias in the form `NAME=MODEL` (e.g., `fast=gpt-4o-mini`). If `--model` matches NAME
+    /// (case-insensitively), MODEL is used instead. Can be specified multiple times.
+    #[arg(
+        long,
+        value_name = "NAME=MODEL",
+        env = "CHATGPT_MODEL_ALIASES",
+        value_delimiter = ',',
+        value_parser = parse_model_alias
+    )]
+    model_alias: Vec<(String, String)>,
+
+    /// Model pricing in the form `MODEL=IN:OUT`, where IN and OUT are the prices in dollars per million
+    /// input and output tokens (e.g., `gpt-4o=2.5:10`). Used by `--show-cost`. Can be specified multiple times.
+    #[arg(
+        long,
+        value_name = "MODEL=IN:OUT",
+        env = "CHATGPT_MODEL_PRICING",
+        value_delimiter = ',',
+        value_parser = parse_model_pricing
+    )]
+    model_pricing: Vec<(String, ModelPricing)>,
+
+    /// If specified, the estimated cost of the request is printed to stderr with the token usage summary.
+    /// The price of the model must be given by `--model-pricing`.
+    #[arg(long, conflicts_with = "quiet")]
+    show_cost: bool,
 
     /// If specified, the system role message will be added to the beginning of the conversation.
     #[arg(long, value_name = "SYSTEM_MESSAGE", env = "CHATGPT_SYSTEM_MESSAGE")]
     system: Option<String>,
 
+    /// Like `--system`, but reads the system role message from the file.
+    #[arg(
+        short = 'S',
+        long,
+        value_name = "SYSTEM_MESSAGE_FILE",
+        conflicts_with = "system"
+    )]
+    system_file: Option<PathBuf>,
+
     /// If specified, HTTP request and response body JSONs are printed to stderr.
     #[arg(long)]
     verbose: bool,
 
+    /// If specified, the request body JSON is printed to stdout instead of being sent to the API.
+    #[arg(long)]
+    dry_run: bool,
+
     #[arg(short, long)]
     echo_input: bool,
+
+    /// Reasoning effort for OpenAI reasoning models (e.g., o1, o3). Ignored with a warning for other models.
+    #[arg(long, value_name = "EFFORT", env = "CHATGPT_REASONING_EFFORT")]
+    reasoning_effort: Option<ReasoningEffort>,
+
+    /// Maximum number of tokens that the model can generate for the reply.
+    #[arg(
+        long,
+        value_name = "MAX_TOKENS",
+        env = "CHATGPT_MAX_TOKENS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_tokens: Option<u32>,
+
+    /// Sampling temperature between 0.0 and 2.0. If omitted, the API default is used.
+    #[arg(
+        long,
+        value_name = "TEMPERATURE",
+        env = "CHATGPT_TEMPERATURE",
+        value_parser = parse_temperature
+    )]
+    temperature: Option<f32>,
+
+    /// Seed for best-effort deterministic sampling (combine with `--temperature 0` for reproducible replies).
+    #[arg(
+        long,
+        value_name = "SEED",
+        env = "CHATGPT_SEED",
+        allow_negative_numbers = true
+    )]
+    seed: Option<i64>,
+
+    /// Sequence where the model stops generating further tokens (can be specified multiple times).
+    #[arg(long, value_name = "SEQUENCE")]
+    stop: Vec<String>,
+
+    /// User-Agent header sent with HTTP requests.
+    #[arg(
+        long,
+        value_name = "USER_AGENT",
+        env = "DABERU_USER_AGENT",
+        default_value = concat!("daberu/", env!("CARGO_PKG_VERSION"))
+    )]
+    user_agent: String,
+
+    /// If specified, stdout is not flushed after each streamed token, which improves throughput when piping.
+    /// This is implied when stdout is not a terminal.
+    #[arg(long)]
+    no_flush: bool,
+
+    /// If specified, the elapsed time is not shown on stderr while waiting for the first token.
+    /// It is only shown when stderr is a terminal.
+    #[arg(long)]
+    no_spinner: bool,
+
+    /// If specified, the raw response body is saved to the file before it is parsed (useful for bug reports).
+    /// A streamed response (server-sent events) is saved line by line as it is read.
+    #[arg(long, visible_alias = "save-stream", value_name = "FILE_PATH")]
+    save_raw: Option<PathBuf>,
+
+    /// Replay a stream saved by `--save-raw` without making any API call, and print the parsed reply.
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        conflicts_with_all = [
+            "save_raw", "message", "message_option", "log", "append", "log_stdin", "dry_run",
+            "echo_input", "clear_cache",
+        ]
+    )]
+    replay_stream: Option<PathBuf>,
+
+    /// If specified, only the first JSON value found in the reply is printed (fenced ```json blocks are preferred).
+    #[arg(long, conflicts_with_all = ["log_stdin", "output", "echo_input"])]
+    extract_json: bool,
+
+    /// Output format. With `json`, the reply is not streamed and a single JSON object is printed at the end.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "text",
+        conflicts_with_all = ["log_stdin", "echo_input"]
+    )]
+    output: OutputFormat,
+
+    /// Shell command that the complete reply is piped through before being shown (e.g., `glow -`).
+    /// If the command fails, the reply is shown unchanged.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        env = "DABERU_RESPONSE_FILTER",
+        conflicts_with_all = ["extract_json", "log_stdin", "output"]
+    )]
+    response_filter: Option<String>,
+
+    /// If specified, the token usage summary is not printed to stderr.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Maximum number of retries when the API returns a transient error (HTTP 429, 500, 502, 503 or 529).
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "DABERU_MAX_RETRIES",
+        default_value_t = 3
+    )]
+    max_retries: u32,
+
+    /// Base delay in seconds of the exponential backoff between retries (ignored if the API sends `retry-after`).
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "DABERU_RETRY_BASE_DELAY",
+        default_value_t = 1.0,
+        value_parser = parse_retry_base_delay
+    )]
+    retry_base_delay: f64,
+
+    /// Timeout in seconds for the whole request, including reading the (streamed) reply (1 to 86400).
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "DABERU_TIMEOUT",
+        default_value_t = 600,
+        value_parser = clap::value_parser!(u64).range(1..=86_400)
+    )]
+    timeout: u64,
+
+    /// Timeout in seconds for establishing a connection to the API server (1 to 86400).
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "DABERU_CONNECT_TIMEOUT",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..=86_400)
+    )]
+    connect_timeout: u64,
+
+    /// Estimated token budget for the whole conversation. If exceeded, the oldest turns (but not the system
+    /// message) are left out of the request. The log file still keeps the full conversation.
+    #[arg(
+        long,
+        value_name = "TOKENS",
+        env = "CHATGPT_MAX_CONTEXT_TOKENS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_context_tokens: Option<u32>,
+
+    /// Directory where replies are cached, keyed by the request. An identical request reuses the cached reply.
+    #[arg(long, value_name = "DIR_PATH", env = "DABERU_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// If specified, the cache is not read (but a fresh reply is still stored if `--cache-dir` is given).
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Remove all cached replies from `--cache-dir` and exit.
+    #[arg(long, requires = "cache_dir")]
+    clear_cache: bool,
+
+    /// List the models available from the API (`GET <OPENAI_BASE_URL>/models`) as JSON and exit.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "message", "message_option", "log_stdin", "dry_run", "echo_input", "replay_stream",
+            "extract_json", "output", "response_filter", "clear_cache",
+        ]
+    )]
+    list_models: bool,
+
+    #[arg(skip)]
+    interrupt: Option<Arc<Interrupt>>,
 }
 
 impl ChatGpt {
-    pub fn call(&self) -> orfail::Result<()> {
-        let request = RequestBody::new(self).or_fail()?;
+    /// Sets the state through which a Ctrl-C handler can stop a streamed reply (see [`Interrupt`]).
+    pub fn set_interrupt(&mut self, interrupt: Arc<Interrupt>) {
+        self.interrupt = Some(interrupt);
+    }
+
+    pub fn call(&self) -> Result<(), DaberuError> {
+        let flush = !self.no_flush && std::io::stdout().is_terminal();
+        self.call_inner(&mut std::io::stdout(), flush)
+    }
+
+    /// Like [`ChatGpt::call()`], but writes the reply (and any other stdout output) to `out`.
+    /// Unless `--no-flush` is specified, `out` is flushed after each streamed token.
+    pub fn call_with_writer(&self, out: &mut dyn Write) -> Result<(), DaberuError> {
+        self.call_inner(out, !self.no_flush)
+    }
+
+    fn call_inner(&self, out: &mut dyn Write, flush: bool) -> Result<(), DaberuError> {
+        if self.clear_cache {
+            self.clear_cache().or_fail()?;
+            return Ok(());
+        }
+        if self.list_models {
+            return self.list_models(out);
+        }
+        if let Some(path) = &self.replay_stream {
+            let file = std::fs::File::open(path)
+                .or_fail_with(|e| format!("failed to open {} ({e})", path.display()))?;
+            let reply = self.handle_stream_response(file, None, None, out, flush)?;
+            self.report_usage(&reply, None);
+            return self.output_reply(&reply, out);
+        }
+
+        if !self.dry_run {
+            self.api_key()?;
+        }
+        let request = RequestBody::new(self)?;
+        if self.dry_run {
+            writeln!(out, "{}", serde_json::to_string_pretty(&request).or_fail()?).or_fail()?;
+            return Ok(());
+        }
         if self.verbose {
             eprintln!("{}", serde_json::to_string_pretty(&request).or_fail()?);
         }
 
-        let response = ureq::post("https://api.openai.com/v1/chat/completions")
-            .set("Content-Type", "application/json")
-            .set("Authorization", &format!("Bearer {}", self.api_key))
-            .send_json(&request)
-            .or_fail()?;
+        let cache_path = self.cache_path(&request).or_fail()?;
+        let cached = cache_path
+            .as_ref()
+            .filter(|_| !self.no_cache)
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice::<CacheEntry>(&bytes).ok());
+        let reply = if let Some(CacheEntry { message, model }) = cached {
+            if !self.quiet {
+                eprintln!("note: the reply was read from the cache");
+            }
+            self.echo_input(&request, out).or_fail()?;
+            if self.prints_reply() {
+                writeln!(out, "{}", message.content).or_fail()?;
+            }
+            Reply {
+                message,
+                model,
+                usage: None,
+            }
+        } else {
+            let api_key = self.api_key()?;
+            let response = self.post(api_key, &request)?;
+            self.echo_input(&request, out).or_fail()?;
+            let reply = if self.verbose {
+                self.handle_response(response, out)?
+            } else {
+                // Started after `post()` so that its retry warnings do not mix with the spinner line.
+                let spinner =
+                    (!self.no_spinner && std::io::stderr().is_terminal()).then(Spinner::start);
+                let reader = response.into_reader();
+                self.handle_stream_response(reader, Some(&request), spinner, out, flush)?
+            };
+            if let Some(path) = &cache_path {
+                self.save_cache(path, &reply).or_fail()?;
+            }
+            reply
+        };
+        self.report_usage(&reply, Some(&request.model));
+
+        let mut log = request.to_log();
+        if reply.message.content.trim().is_empty() {
+            eprintln!(
+                "warning: the model returned no text, so the empty reply is not saved to the log"
+            );
+        } else {
+            log.push(reply.message.clone());
+        }
+        if self.log.is_some() {
+            self.write_log(&log).or_fail()?;
+        } else if self.log_stdin {
+            serde_json::to_writer(&mut *out, &log).or_fail()?;
+            writeln!(out).or_fail()?;
+        }
+
+        self.output_reply(&reply, out)
+    }
+
+    /// Writes the reply in the forms that are not streamed (`--response-filter`, `--extract-json` and `--output json`).
+    fn output_reply(&self, reply: &Reply, out: &mut dyn Write) -> Result<(), DaberuError> {
+        if let Some(command) = &self.response_filter {
+            let reason = match filter_response(command, &reply.message.content) {
+                Ok(output) if output.status.success() => {
+                    out.write_all(&output.stdout).or_fail()?;
+                    None
+                }
+                Ok(output) => Some(output.status.to_string()),
+                Err(e) => Some(e.message),
+            };
+            if let Some(reason) = reason {
+                eprintln!(
+                    "warning: the response filter failed ({reason}), so the reply is shown unfiltered"
+                );
+                writeln!(out, "{}", reply.message.content).or_fail()?;
+            }
+        }
+        if self.extract_json {
+            let json = extract_json(&reply.message.content)
+                .or_fail_with(|()| "no JSON value found in the reply".to_owned())?;
+            writeln!(out, "{json}").or_fail()?;
+        }
+        if self.output == OutputFormat::Json {
+            let output = serde_json::json!({
+                "content": reply.message.content,
+                "model": reply.model,
+                "finish_reason": reply.message.finish_reason,
+                "usage": reply.usage,
+            });
+            writeln!(out, "{output}").or_fail()?;
+        }
+        Ok(())
+    }
+
+    fn api_key(&self) -> Result<&str, DaberuError> {
+        let api_key = self.api_key.as_deref().unwrap_or_default();
+        let problem = if self.api_key.is_none() {
+            "missing"
+        } else if api_key.trim().is_empty() {
+            "blank"
+        } else if api_key.trim() != api_key {
+            "surrounded by whitespace"
+        } else {
+            return Ok(api_key);
+        };
+        Err(DaberuError::usage(format!(
+            "the OpenAI API key is {problem}; set the OPENAI_API_KEY environment variable or pass --api-key"
+        )))
+    }
 
+    /// Resolves the model name from `--model`, the model recorded in the log (`--model-from-log`) and the aliases.
+    fn resolve_model(&self, log_model: Option<&str>) -> String {
+        let model = match (&self.model, log_model) {
+            (Some(model), _) => model.as_str(),
+            (None, Some(model)) if self.model_from_log => model,
+            (None, _) => {
+                if self.model_from_log {
+                    eprintln!("warning: no model is recorded in the conversation history, so {DEFAULT_MODEL:?} is used");
+                }
+                DEFAULT_MODEL
+            }
+        };
+        self.model_alias
+            .iter()
+            .rev()
+            .find(|(name, _)| name.eq_ignore_ascii_case(model))
+            .map_or(model, |(_, model)| model)
+            .to_owned()
+    }
+
+    fn check_model(&self, model: &str) -> Result<(), DaberuError> {
+        if self.known_models.is_empty() || self.known_models.iter().any(|m| m == model) {
+            return Ok(());
+        }
+
+        let mut message = format!("unknown model {model:?}");
+        if let Some((distance, suggestion)) = self
+            .known_models
+            .iter()
+            .map(|m| (edit_distance(m, model), m))
+            .min()
+        {
+            if distance <= 3 {
+                message.push_str(&format!(" (did you mean {suggestion:?}?)"));
+            }
+        }
+        if self.strict_model {
+            return Err(DaberuError::usage(message));
+        }
+        eprintln!("warning: {message}");
+        Ok(())
+    }
+
+    fn post(&self, api_key: &str, request: &RequestBody) -> Result<ureq::Response, DaberuError> {
+        let agent = self.agent();
+        let url = format!("{}/chat/completions", self.openai_base_url);
+        let http_request = self
+            .authorize(agent.post(&url), api_key)
+            .set("Content-Type", "application/json");
+        let result = self.send(http_request, Some(request));
+        match result {
+            Err(DaberuError::Http { status: 413, .. }) => {
+                let body = request.too_large_message().or_fail()?;
+                Err(DaberuError::Http { status: 413, body })
+            }
+            result => result,
+        }
+    }
+
+    fn list_models(&self, out: &mut dyn Write) -> Result<(), DaberuError> {
+        let api_key = self.api_key()?;
+        let agent = self.agent();
+        let url = format!("{}/models", self.openai_base_url);
+        let response = self.send(self.authorize(agent.get(&url), api_key), None)?;
+        let body = response.into_string().map_err(|e| self.read_error(e))?;
+        let models: serde_json::Value =
+            serde_json::from_str(&body).map_err(|e| DaberuError::Parse {
+                message: e.to_string(),
+            })?;
+        writeln!(out, "{}", serde_json::to_string_pretty(&models).or_fail()?).or_fail()?;
+        Ok(())
+    }
+
+    fn agent(&self) -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(self.timeout))
+            .timeout_connect(Duration::from_secs(self.connect_timeout))
+            .build()
+    }
+
+    fn authorize(&self, request: ureq::Request, api_key: &str) -> ureq::Request {
+        let request = request.set("User-Agent", &self.user_agent);
+        if let Some(version) = &self.azure_api_version {
+            request
+                .query("api-version", version)
+                .set("api-key", api_key)
+        } else {
+            request.set("Authorization", &format!("Bearer {api_key}"))
+        }
+    }
+
+    /// Sends `http_request` (with `body` as JSON if given), retrying on transient errors.
+    fn send(
+        &self,
+        http_request: ureq::Request,
+        body: Option<&RequestBody>,
+    ) -> Result<ureq::Response, DaberuError> {
+        let mut retries = 0;
+        loop {
+            let result = match body {
+                Some(body) => http_request.clone().send_json(body),
+                None => http_request.clone().call(),
+            };
+            match result {
+                Err(ureq::Error::Status(status, response))
+                    if retries < self.max_retries && is_retryable_status(status) =>
+                {
+                    let delay = self.retry_delay(retries, response.header("retry-after"));
+                    retries += 1;
+                    eprintln!(
+                        "warning: the API returned HTTP {status}, retrying in {:.1}s ({retries}/{})",
+                        delay.as_secs_f64(),
+                        self.max_retries
+                    );
+                    std::thread::sleep(delay);
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    return Err(DaberuError::from_status(status, body));
+                }
+                Err(e) if is_timeout(&e) => {
+                    let message = format!(
+                        "request timed out (connect timeout: {}s, timeout: {}s)",
+                        self.connect_timeout, self.timeout
+                    );
+                    return Err(DaberuError::Timeout { message });
+                }
+                Err(e) => return Err(DaberuError::Other(Failure::new(e))),
+                Ok(response) => return Ok(response),
+            }
+        }
+    }
+
+    fn retry_delay(&self, retries: u32, retry_after: Option<&str>) -> Duration {
+        // `try_from_secs_f64()` fails on overflow, infinity and NaN, all of which mean "too long" here.
+        let to_delay = |secs: f64| {
+            Duration::try_from_secs_f64(secs.max(0.0))
+                .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+        };
+        if let Some(secs) = retry_after.and_then(|v| v.trim().parse::<f64>().ok()) {
+            return to_delay(secs);
+        }
+
+        // Exponential backoff with jitter derived from the clock (no RNG dependency needed).
+        let base = self.retry_base_delay * 2f64.powi(retries as i32);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let jitter = self.retry_base_delay * f64::from(nanos) / 1e9;
+        to_delay(base + jitter)
+    }
+
+    fn read_error(&self, e: std::io::Error) -> DaberuError {
+        if is_timeout(&e) {
+            let message = format!("request timed out after {}s", self.timeout);
+            DaberuError::Timeout { message }
+        } else {
+            DaberuError::Other(Failure::new(e))
+        }
+    }
+
+    fn echo_input(&self, request: &RequestBody, out: &mut dyn Write) -> orfail::Result<()> {
         if self.echo_input {
-            println!("Input");
-            println!("=====");
-            println!();
-            println!("```console");
-            println!(
+            writeln!(out, "Input").or_fail()?;
+            writeln!(out, "=====").or_fail()?;
+            writeln!(out).or_fail()?;
+            writeln!(out, "```console").or_fail()?;
+            writeln!(
+                out,
                 "$ echo -e {:?} | daberu {}",
                 request.messages.last().or_fail()?.content.trim(),
                 std::env::args().skip(1).collect::<Vec<_>>().join(" ")
-            );
-            println!("```");
-            println!();
-            println!("Output");
-            println!("======");
-            println!();
+            )
+            .or_fail()?;
+            writeln!(out, "```").or_fail()?;
+            writeln!(out).or_fail()?;
+            writeln!(out, "Output").or_fail()?;
+            writeln!(out, "======").or_fail()?;
+            writeln!(out).or_fail()?;
         }
+        Ok(())
+    }
 
-        let reply = if self.verbose {
-            self.handle_response(response).or_fail()?
-        } else {
-            self.handle_stream_response(response).or_fail()?
+    fn write_log(&self, log: &[Message]) -> orfail::Result<()> {
+        let path = self.log.as_ref().or_fail()?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .or_fail()?;
+        serde_json::to_writer(file, log).or_fail()?;
+        Ok(())
+    }
+
+    fn cache_path(&self, request: &RequestBody) -> orfail::Result<Option<PathBuf>> {
+        let Some(dir) = &self.cache_dir else {
+            return Ok(None);
         };
+        // The endpoint is part of the key, as the same request may get different replies from different servers.
+        let key = serde_json::to_vec(&(&self.openai_base_url, &self.azure_api_version, request))
+            .or_fail()?;
+        Ok(Some(dir.join(format!("{:016x}.json", fnv1a64(&key)))))
+    }
 
-        if let Some(log) = &self.log {
-            let file = std::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(log)
-                .or_fail()?;
-            let mut log = request.messages;
-            log.push(reply);
-            serde_json::to_writer(file, &log).or_fail()?;
+    fn save_cache(&self, path: &Path, reply: &Reply) -> orfail::Result<()> {
+        if reply.message.incomplete || reply.message.content.trim().is_empty() {
+            return Ok(());
         }
+        let entry = CacheEntry {
+            message: reply.message.clone(),
+            model: reply.model.clone(),
+        };
+        let dir = path.parent().or_fail()?;
+        std::fs::create_dir_all(dir)
+            .or_fail_with(|e| format!("failed to create {} ({e})", dir.display()))?;
+        std::fs::write(path, serde_json::to_vec(&entry).or_fail()?)
+            .or_fail_with(|e| format!("failed to write {} ({e})", path.display()))?;
+        Ok(())
+    }
 
+    fn clear_cache(&self) -> orfail::Result<()> {
+        let dir = self.cache_dir.as_ref().or_fail()?;
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Ok(());
+        };
+        let mut count = 0;
+        for entry in entries {
+            let path = entry.or_fail()?.path();
+            if is_cache_file(&path) {
+                std::fs::remove_file(&path)
+                    .or_fail_with(|e| format!("failed to remove {} ({e})", path.display()))?;
+                count += 1;
+            }
+        }
+        if !self.quiet {
+            eprintln!("removed {count} cached replies from {}", dir.display());
+        }
         Ok(())
     }
 
-    fn handle_stream_response(&self, response: ureq::Response) -> orfail::Result<Message> {
+    fn report_usage(&self, reply: &Reply, requested_model: Option<&str>) {
+        if self.quiet {
+            return;
+        }
+        let Some(usage) = reply.usage else {
+            return;
+        };
+        let tokens = format!(
+            "tokens: in={} out={}",
+            usage.prompt_tokens, usage.completion_tokens
+        );
+        if !self.show_cost {
+            eprintln!("{tokens}");
+            return;
+        }
+        let models = requested_model
+            .into_iter()
+            .chain(reply.model.as_deref())
+            .collect::<Vec<_>>();
+        match self.estimate_cost(usage, &models) {
+            Some(cost) => eprintln!("{tokens} cost=${cost:.6}"),
+            None => eprintln!(
+                "{tokens} (cost unknown: no --model-pricing for {})",
+                models
+                    .first()
+                    .map_or_else(|| "the model".to_owned(), |m| format!("{m:?}"))
+            ),
+        }
+    }
+
+    /// Estimates the cost in dollars using the price of the first of `models` found in `--model-pricing`.
+    fn estimate_cost(&self, usage: Usage, models: &[&str]) -> Option<f64> {
+        let pricing = models.iter().find_map(|model| {
+            self.model_pricing
+                .iter()
+                .rev()
+                .find(|(name, _)| name.eq_ignore_ascii_case(model))
+                .map(|(_, pricing)| pricing)
+        })?;
+        Some(
+            (usage.prompt_tokens as f64 * pricing.input
+                + usage.completion_tokens as f64 * pricing.output)
+                / 1_000_000.0,
+        )
+    }
+
+    fn prints_reply(&self) -> bool {
+        if self.extract_json || self.output == OutputFormat::Json || self.response_filter.is_some()
+        {
+            return false;
+        }
+        !(self.log_stdin && self.log.is_none())
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt.as_ref().is_some_and(|i| i.is_requested())
+    }
+
+    fn handle_stream_response<R: Read + Send + 'static>(
+        &self,
+        reader: R,
+        request: Option<&RequestBody>,
+        mut spinner: Option<Spinner>,
+        out: &mut dyn Write,
+        flush: bool,
+    ) -> Result<Reply, DaberuError> {
         #[derive(Debug, serde::Deserialize)]
         struct Data {
+            model: Option<String>,
             choices: Vec<Choice>,
+            usage: Option<Usage>,
         }
 
         #[derive(Debug, serde::Deserialize)]
@@ -105,9 +798,68 @@ impl ChatGpt {
         }
 
         let mut content = String::new();
-        let reader = BufReader::new(response.into_reader());
-        for line in reader.lines() {
-            let line = line.or_fail()?;
+        let mut model = None;
+        let mut finish_reason = None;
+        let mut usage = None;
+        let mut terminated = false;
+        let mut save_raw = self
+            .save_raw
+            .as_ref()
+            .map(std::fs::File::create)
+            .transpose()
+            .or_fail()?;
+
+        // Ctrl-C is only held back while a live reply is streamed, so that it can be saved.
+        let _streaming = self
+            .interrupt
+            .as_ref()
+            .filter(|_| request.is_some())
+            .map(|i| i.start_streaming());
+
+        // Lines are read on another thread so that an interrupt is noticed even while the stream is stalled.
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let mut line = String::new();
+                let result = reader.read_line(&mut line).map(|_| line);
+                let done = !matches!(&result, Ok(line) if !line.is_empty());
+                if tx.send(result).is_err() || done {
+                    break;
+                }
+            }
+        });
+        loop {
+            let received = match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(received) => Some(received),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if self.interrupted() {
+                if let Some(request) = request {
+                    self.save_partial_reply(request, content, model, out)
+                        .or_fail()?;
+                }
+                return Err(DaberuError::Interrupted);
+            }
+            let buf = match received {
+                None => continue,
+                Some(Ok(line)) if line.is_empty() => break,
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    let error = self.read_error(e);
+                    if let Some(request) = request {
+                        self.save_partial_reply(request, content, model, out)
+                            .or_fail()?;
+                    }
+                    return Err(error);
+                }
+            };
+            if let Some(file) = &mut save_raw {
+                file.write_all(buf.as_bytes()).or_fail()?;
+            }
+
+            let line = buf.trim_end_matches(['\r', '\n']);
             if line.is_empty() {
                 continue;
             }
@@ -115,32 +867,104 @@ impl ChatGpt {
                 continue;
             }
             if line == "data: [DONE]" {
+                terminated = true;
                 break;
             }
 
-            let data: Data = serde_json::from_str(&line["data: ".len()..])
-                .or_fail_with(|e| format!("failed to parse line: {line} ({e})"))?;
-            (!data.choices.is_empty()).or_fail()?;
-            if let Some(reason) = data.choices[0].finish_reason {
+            let data: Data = serde_json::from_str(&line["data: ".len()..]).map_err(|e| {
+                let message = format!("failed to parse line: {line} ({e})");
+                DaberuError::Parse { message }
+            })?;
+            if model.is_none() {
+                model = data.model;
+            }
+            if data.usage.is_some() {
+                usage = data.usage;
+            }
+            let Some(choice) = data.choices.first() else {
+                // The final chunk that only carries `usage` has no choices.
+                continue;
+            };
+            if let Some(reason) = choice.finish_reason {
                 reason.check().or_fail()?;
+                finish_reason = Some(reason);
+                terminated = true;
             }
 
-            content.push_str(&data.choices[0].delta.content);
-            print!("{}", data.choices[0].delta.content);
-            std::io::stdout().flush().or_fail()?;
+            if !choice.delta.content.is_empty() {
+                // Clear the spinner before the first token is shown.
+                spinner.take();
+            }
+            content.push_str(&choice.delta.content);
+            if self.prints_reply() {
+                write!(out, "{}", choice.delta.content).or_fail()?;
+                if flush {
+                    out.flush().or_fail()?;
+                }
+            }
+        }
+        if self.prints_reply() {
+            writeln!(out).or_fail()?;
+        }
+        if !terminated {
+            eprintln!(
+                "warning: the response stream ended unexpectedly, so the reply may be incomplete"
+            );
         }
-        println!();
 
-        Ok(Message {
+        Ok(Reply {
+            message: Message {
+                role: Role::Assistant,
+                content,
+                finish_reason,
+                incomplete: !terminated,
+                model: model.clone(),
+            },
+            model,
+            usage,
+        })
+    }
+
+    /// Saves the reply received before the stream was interrupted to `--log`, so that it is not lost.
+    fn save_partial_reply(
+        &self,
+        request: &RequestBody,
+        content: String,
+        model: Option<String>,
+        out: &mut dyn Write,
+    ) -> orfail::Result<()> {
+        if content.trim().is_empty() {
+            return Ok(());
+        }
+        if self.prints_reply() {
+            writeln!(out).or_fail()?;
+        }
+        if self.log.is_none() {
+            return Ok(());
+        }
+        let mut log = request.to_log();
+        log.push(Message {
             role: Role::Assistant,
             content,
-        })
+            finish_reason: None,
+            incomplete: true,
+            model: model.or_else(|| Some(request.model.clone())),
+        });
+        self.write_log(&log).or_fail()?;
+        eprintln!("warning: the response stream was interrupted, so the partial reply is saved to the log");
+        Ok(())
     }
 
-    fn handle_response(&self, response: ureq::Response) -> orfail::Result<Message> {
+    fn handle_response(
+        &self,
+        response: ureq::Response,
+        out: &mut dyn Write,
+    ) -> Result<Reply, DaberuError> {
         #[derive(Debug, serde::Deserialize)]
         struct ResponseBody {
+            model: Option<String>,
             choices: Vec<Choice>,
+            usage: Option<Usage>,
         }
 
         #[derive(Debug, serde::Deserialize)]
@@ -149,7 +973,15 @@ impl ChatGpt {
             finish_reason: FinishReason,
         }
 
-        let response_json: serde_json::Value = response.into_json().or_fail()?;
+        let body = response.into_string().map_err(|e| self.read_error(e))?;
+        if let Some(path) = &self.save_raw {
+            std::fs::write(path, &body)
+                .or_fail_with(|e| format!("failed to write {} ({e})", path.display()))?;
+        }
+        let response_json: serde_json::Value =
+            serde_json::from_str(&body).map_err(|e| DaberuError::Parse {
+                message: e.to_string(),
+            })?;
 
         if self.verbose {
             eprintln!(
@@ -158,11 +990,23 @@ impl ChatGpt {
             );
         }
 
-        let response: ResponseBody = serde_json::from_value(response_json).or_fail()?;
+        let response: ResponseBody =
+            serde_json::from_value(response_json).map_err(|e| DaberuError::Parse {
+                message: e.to_string(),
+            })?;
         let choice = response.choices.into_iter().next().or_fail()?;
         choice.finish_reason.check().or_fail()?;
-        println!("{}", choice.message.content);
-        Ok(choice.message)
+        if self.prints_reply() {
+            writeln!(out, "{}", choice.message.content).or_fail()?;
+        }
+        let mut message = choice.message;
+        message.finish_reason = Some(choice.finish_reason);
+        message.model = response.model.clone();
+        Ok(Reply {
+            message,
+            model: response.model,
+            usage: response.usage,
+        })
     }
 }
 
@@ -170,45 +1014,444 @@ impl ChatGpt {
 pub struct RequestBody {
     model: String,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(rename = "messages")]
+    api_messages: Vec<ApiMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+
+    /// Messages of this request, including the log-only fields that are not sent to the API.
+    #[serde(skip)]
     messages: Vec<Message>,
+
+    /// Conversation history that is saved to the log but not sent (used by `--append`).
+    #[serde(skip)]
+    history: Vec<Message>,
 }
 
 impl RequestBody {
-    pub fn new(chatgpt: &ChatGpt) -> orfail::Result<Self> {
-        let mut messages = Vec::new();
-        if let Some(log) = &chatgpt.log {
+    pub fn new(chatgpt: &ChatGpt) -> Result<Self, DaberuError> {
+        let message = chatgpt.message.as_ref().or(chatgpt.message_option.as_ref());
+        if chatgpt.log_stdin && message.is_none() {
+            return Err(DaberuError::usage(
+                "--log-stdin requires the message to be given by MESSAGE or --message",
+            ));
+        }
+
+        let mut messages: Vec<Message> = Vec::new();
+        if chatgpt.log_stdin {
+            messages = serde_json::from_reader(std::io::stdin()).or_fail_with(|e| {
+                format!("failed to read conversation history from stdin ({e})")
+            })?;
+        } else if let Some(log) = &chatgpt.log {
             if let Ok(file) = std::fs::File::open(log) {
                 messages = serde_json::from_reader(file).or_fail()?;
             }
         }
 
+        if let Some(last) = messages.last() {
+            if last.incomplete || last.finish_reason == Some(FinishReason::Length) {
+                eprintln!("warning: the last reply in the conversation history is incomplete");
+            }
+        }
+
+        let log_model = messages
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::Assistant)
+            .and_then(|m| m.model.clone());
+
+        let mut history = Vec::new();
+        if chatgpt.append {
+            history = std::mem::take(&mut messages);
+            messages.extend(history.first().filter(|m| m.role == Role::System).cloned());
+        }
+
+        let system = match &chatgpt.system_file {
+            Some(path) => {
+                let mut content = std::fs::read_to_string(path).or_fail_with(|e| {
+                    format!(
+                        "failed to read system message file {} ({e})",
+                        path.display()
+                    )
+                })?;
+                if content.ends_with('\n') {
+                    content.pop();
+                }
+                Some(content)
+            }
+            None => chatgpt.system.clone(),
+        };
         if messages.is_empty() {
-            if let Some(system) = &chatgpt.system {
+            if let Some(system) = system {
                 messages.push(Message {
                     role: Role::System,
-                    content: system.clone(),
+                    content: system,
+                    finish_reason: None,
+                    incomplete: false,
+                    model: None,
                 });
             }
         }
 
-        let mut message = String::new();
-        std::io::stdin().read_to_string(&mut message).or_fail()?;
+        let message = match message {
+            Some(message) => message.clone(),
+            None => {
+                let mut message = String::new();
+                std::io::stdin().read_to_string(&mut message).or_fail()?;
+                message
+            }
+        };
+        if message.trim().is_empty() {
+            return Err(DaberuError::usage("the input message is empty"));
+        }
         messages.push(Message {
             role: Role::User,
-            content: message.clone(),
+            content: message,
+            finish_reason: None,
+            incomplete: false,
+            model: None,
         });
+
+        if let Some(budget) = chatgpt.max_context_tokens {
+            let full = messages.clone();
+            let dropped = trim_messages(&mut messages, budget as usize);
+            if dropped > 0 {
+                eprintln!("warning: the {dropped} oldest turns are not sent to keep the conversation within --max-context-tokens");
+                if history.is_empty() {
+                    history = full;
+                    history.pop();
+                }
+            }
+            let estimate = estimate_tokens(&messages);
+            if estimate > budget as usize {
+                eprintln!("warning: the messages that cannot be left out (about {estimate} tokens) still exceed --max-context-tokens");
+            }
+        }
+
+        let model = chatgpt.resolve_model(log_model.as_deref());
+        chatgpt.check_model(&model)?;
+        let mut reasoning_effort = chatgpt.reasoning_effort;
+        if reasoning_effort.is_some() && !is_reasoning_model(&model) {
+            eprintln!(
+                "warning: --reasoning-effort is ignored because {model:?} is not a reasoning model"
+            );
+            reasoning_effort = None;
+        }
+
+        let api_messages = messages
+            .iter()
+            .map(|m| ApiMessage {
+                role: m.role.api_name(&model),
+                content: m.content.clone(),
+            })
+            .collect();
         Ok(Self {
-            model: chatgpt.model.clone(),
+            model,
             stream: !chatgpt.verbose,
+            stream_options: (!chatgpt.verbose).then_some(StreamOptions {
+                include_usage: true,
+            }),
+            api_messages,
             messages,
+            reasoning_effort,
+            max_completion_tokens: chatgpt.max_tokens,
+            temperature: chatgpt.temperature,
+            seed: chatgpt.seed,
+            stop: chatgpt.stop.clone(),
+            history,
         })
     }
+
+    fn to_log(&self) -> Vec<Message> {
+        if self.history.is_empty() {
+            return self.messages.clone();
+        }
+        let mut log = self.history.clone();
+        if let Some(system) = self.messages.first().filter(|m| m.role == Role::System) {
+            // A system message given by `--system` when the history has none (`--append`).
+            if log.first().is_none_or(|m| m.role != Role::System) {
+                log.insert(0, system.clone());
+            }
+        }
+        log.extend(self.messages.last().cloned());
+        log
+    }
+
+    fn too_large_message(&self) -> orfail::Result<String> {
+        let (last, history) = self.messages.split_last().or_fail()?;
+        let mut system = 0;
+        let mut past = 0;
+        for message in history {
+            let size = serde_json::to_vec(message).or_fail()?.len();
+            if message.role == Role::System {
+                system += size;
+            } else {
+                past += size;
+            }
+        }
+        let input = serde_json::to_vec(last).or_fail()?.len();
+        let total = serde_json::to_vec(self).or_fail()?.len();
+        Ok(format!(
+            "The request is too large for the API.
+
+Approximate request size: {total} bytes
+  - system message:       {system} bytes
+  - conversation history: {past} bytes
+  - input message:        {input} bytes
+
+Suggestions:
+  - Start a new conversation (use another `--log` file) or remove old messages from the log
+  - Limit the conversation history that is sent with `--max-context-tokens`
+  - Shorten the input message"
+        ))
+    }
+}
+
+fn filter_response(command: &str, content: &str) -> orfail::Result<std::process::Output> {
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .or_fail_with(|e| format!("failed to spawn {command:?} ({e})"))?;
+
+    // Writes from another thread so that a filter producing output early cannot deadlock us.
+    let mut stdin = child.stdin.take().or_fail()?;
+    let mut content = content.to_owned();
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    let writer = std::thread::spawn(move || stdin.write_all(content.as_bytes()));
+    let output = child.wait_with_output().or_fail()?;
+    let _ = writer.join();
+    Ok(output)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(current)
+            };
+            prev = current;
+        }
+    }
+    row[b.len()]
+}
+
+fn parse_base_url(s: &str) -> Result<String, String> {
+    let url = s.trim_end_matches('/');
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return Err("the URL must start with `https://` or `http://`".to_owned());
+    }
+    Ok(url.to_owned())
+}
+
+fn is_timeout(mut error: &(dyn std::error::Error + 'static)) -> bool {
+    loop {
+        if let Some(e) = error.downcast_ref::<std::io::Error>() {
+            if e.kind() == std::io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        match error.source() {
+            Some(source) => error = source,
+            None => return false,
+        }
+    }
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 500 | 502 | 503 | 529)
+}
+
+fn parse_retry_base_delay(s: &str) -> Result<f64, String> {
+    let delay: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if !delay.is_finite() || delay <= 0.0 {
+        return Err(format!("{delay} is not a positive number of seconds"));
+    }
+    Ok(delay)
+}
+
+fn parse_temperature(s: &str) -> Result<f32, String> {
+    let temperature: f32 = s.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=2.0).contains(&temperature) {
+        return Err(format!("{temperature} is not in 0.0..=2.0"));
+    }
+    Ok(temperature)
+}
+
+/// Rough token count estimate (about four characters per token).
+fn estimate_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| m.content.chars().count().div_ceil(4))
+        .sum()
+}
+
+/// Drops the oldest user/assistant turns until `messages` fits in `budget` estimated tokens,
+/// keeping the leading system message and the last message. Returns the number of dropped turns.
+fn trim_messages(messages: &mut Vec<Message>, budget: usize) -> usize {
+    let start = usize::from(messages.first().is_some_and(|m| m.role == Role::System));
+    let mut dropped = 0;
+    while estimate_tokens(messages) > budget && messages.len() > start + 1 {
+        let end = (start + 1..messages.len())
+            .find(|&i| messages[i].role == Role::User)
+            .unwrap_or(messages.len() - 1);
+        messages.drain(start..end);
+        dropped += 1;
+    }
+    dropped
+}
+
+fn parse_model_alias(s: &str) -> Result<(String, String), String> {
+    let (name, model) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=MODEL, but got {s:?}"))?;
+    let (name, model) = (name.trim(), model.trim());
+    if name.is_empty() {
+        return Err(format!("the alias name is empty in {s:?}"));
+    }
+    if model.is_empty() {
+        return Err(format!("the alias {name:?} points to an empty model name"));
+    }
+    Ok((name.to_owned(), model.to_owned()))
+}
+
+fn parse_model_pricing(s: &str) -> Result<(String, ModelPricing), String> {
+    let (model, prices) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MODEL=IN:OUT, but got {s:?}"))?;
+    let model = model.trim();
+    if model.is_empty() {
+        return Err(format!("the model name is empty in {s:?}"));
+    }
+    let (input, output) = prices
+        .split_once(':')
+        .ok_or_else(|| format!("expected MODEL=IN:OUT, but got {s:?}"))?;
+    let parse_price = |price: &str| {
+        price
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|price| price.is_finite() && *price >= 0.0)
+            .ok_or_else(|| {
+                format!("invalid price {price:?} for {model:?} (expected a non-negative number)")
+            })
+    };
+    Ok((
+        model.to_owned(),
+        ModelPricing {
+            input: parse_price(input)?,
+            output: parse_price(output)?,
+        },
+    ))
+}
+
+fn extract_json(content: &str) -> Option<serde_json::Value> {
+    let mut rest = content;
+    while let Some(start) = rest.find("```json") {
+        rest = &rest[start + "```json".len()..];
+        let end = rest.find("```").unwrap_or(rest.len());
+        if let Ok(value) = serde_json::from_str(&rest[..end]) {
+            return Some(value);
+        }
+    }
+
+    // Falls back to the largest balanced object or array embedded in the text.
+    let mut largest: Option<(usize, serde_json::Value)> = None;
+    for (i, _) in content.match_indices(['{', '[']) {
+        let mut values =
+            serde_json::Deserializer::from_str(&content[i..]).into_iter::<serde_json::Value>();
+        if let Some(Ok(value)) = values.next() {
+            let len = values.byte_offset();
+            if largest.as_ref().is_none_or(|(n, _)| *n < len) {
+                largest = Some((len, value));
+            }
+        }
+    }
+    largest.map(|(_, value)| value)
+}
+
+fn is_reasoning_model(model: &str) -> bool {
+    let mut chars = model.chars();
+    let o_series = chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit());
+    o_series || model.starts_with("gpt-5")
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StreamOptions {
+    include_usage: bool,
+}
+
+/// Contents of a `--cache-dir` file.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct CacheEntry {
+    message: Message,
+    model: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reply {
+    message: Message,
+    model: Option<String>,
+    usage: Option<Usage>,
+}
+
+/// Prices in dollars per million tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelPricing {
+    input: f64,
+    output: f64,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Message {
     role: Role,
     content: String,
+
+    /// Why the model stopped generating this message (log only; not sent to the API).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+
+    /// Set when the response stream ended without a terminal event (log only; not sent to the API).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    incomplete: bool,
+
+    /// Model that wrote this message (log only; not sent to the API).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+}
+
+/// Message as sent to the API (without the log-only fields of [`Message`]).
+#[derive(Debug, serde::Serialize)]
+struct ApiMessage {
+    role: &'static str,
+    content: String,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
@@ -219,7 +1462,35 @@ pub enum Role {
     Assistant,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+impl Role {
+    /// Role name sent to the API. Reasoning models expect `developer` instead of `system`.
+    fn api_name(self, model: &str) -> &'static str {
+        match self {
+            Self::System if is_reasoning_model(model) => "developer",
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum FinishReason {
     Stop,
@@ -231,12 +1502,143 @@ impl FinishReason {
     pub fn check(self) -> orfail::Result<()> {
         match self {
             Self::Stop => Ok(()),
-            Self::Length => Err(Failure::new(
-                "Incomplete model output due to max_tokens parameter or token limit",
-            )),
+            Self::Length => {
+                eprintln!(
+                    "warning: Incomplete model output due to max_tokens parameter or token limit"
+                );
+                Ok(())
+            }
             Self::ContentFilter => Err(Failure::new(
                 "Omitted content due to a flag from our content filters",
             )),
         }
     }
 }
+
+/// Returns `true` if `path` has the name of a file created by [`ChatGpt::cache_path()`] (16 hex digits + `.json`).
+fn is_cache_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+        && path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| {
+                stem.len() == 16 && stem.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            })
+}
+
+/// 64-bit FNV-1a hash, used because it is stable across builds (unlike `DefaultHasher`).
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Debug, Parser)]
+    struct Args {
+        #[clap(flatten)]
+        chatgpt: ChatGpt,
+    }
+
+    fn request_json(args: &[&str]) -> serde_json::Value {
+        let args = Args::try_parse_from(["daberu"].iter().chain(args)).expect("parse args");
+        let request = RequestBody::new(&args.chatgpt).expect("build request");
+        serde_json::to_value(&request).expect("serialize request")
+    }
+
+    #[test]
+    fn stop_is_omitted_unless_specified() {
+        let json = request_json(&["hi"]);
+        assert!(json.get("stop").is_none());
+
+        let json = request_json(&["--stop", "END", "--stop", "\n\n", "hi"]);
+        assert_eq!(json["stop"], serde_json::json!(["END", "\n\n"]));
+    }
+
+    #[test]
+    fn reasoning_models() {
+        assert!(is_reasoning_model("o3"));
+        assert!(is_reasoning_model("o4-mini"));
+        assert!(is_reasoning_model("gpt-5"));
+        assert!(is_reasoning_model("gpt-5-mini"));
+        assert!(!is_reasoning_model("gpt-4o"));
+        assert!(!is_reasoning_model("omni-moderation-latest"));
+    }
+
+    #[test]
+    fn system_role_name_depends_on_model() {
+        assert_eq!(Role::System.api_name("o3"), "developer");
+        assert_eq!(Role::System.api_name("gpt-5"), "developer");
+        assert_eq!(Role::System.api_name("gpt-4o"), "system");
+        assert_eq!(Role::System.api_name("omni-moderation-latest"), "system");
+        for model in ["o3", "gpt-4o"] {
+            assert_eq!(Role::User.api_name(model), "user");
+            assert_eq!(Role::Assistant.api_name(model), "assistant");
+        }
+
+        let roles = |model| {
+            let json = request_json(&["--model", model, "--system", "Be brief.", "hi"]);
+            json["messages"]
+                .as_array()
+                .expect("messages")
+                .iter()
+                .map(|m| m["role"].as_str().expect("role").to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(roles("o3"), ["developer", "user"]);
+        assert_eq!(roles("gpt-4o"), ["system", "user"]);
+    }
+
+    #[test]
+    fn model_from_log() {
+        let log = std::env::temp_dir().join(format!("daberu-test-{}.json", std::process::id()));
+        std::fs::write(
+            &log,
+            r#"[{"role":"user","content":"hi"},{"role":"assistant","content":"hello","model":"gpt-4.1-mini"}]"#,
+        )
+        .expect("write log");
+        let log = log.to_str().expect("utf-8 path");
+
+        let json = request_json(&["--log", log, "--model-from-log", "hi"]);
+        assert_eq!(json["model"], "gpt-4.1-mini");
+        assert!(json["messages"][1].get("model").is_none());
+
+        let json = request_json(&["--log", log, "--model-from-log", "--model", "o3", "hi"]);
+        assert_eq!(json["model"], "o3");
+
+        let json = request_json(&["--log", log, "hi"]);
+        assert_eq!(json["model"], DEFAULT_MODEL);
+
+        std::fs::remove_file(log).expect("remove log");
+    }
+
+    #[test]
+    fn model_pricing() {
+        assert!(parse_model_pricing("gpt-4o").is_err());
+        assert!(parse_model_pricing("gpt-4o=2.5").is_err());
+        assert!(parse_model_pricing("gpt-4o=-1:10").is_err());
+        assert!(parse_model_pricing("=2.5:10").is_err());
+
+        let args = Args::try_parse_from([
+            "daberu",
+            "--model-pricing",
+            "gpt-4o=5:15,gpt-4o-mini=0.15:0.6",
+            "--model-pricing",
+            "GPT-4o=2.5:10",
+        ])
+        .expect("parse args");
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+        };
+        let cost = |models: &[&str]| args.chatgpt.estimate_cost(usage, models);
+        assert_eq!(cost(&["gpt-4o"]), Some(7.5));
+        assert_eq!(cost(&["gpt-4o-mini"]), Some(0.45));
+        assert_eq!(cost(&["o3", "gpt-4o-mini"]), Some(0.45));
+        assert_eq!(cost(&["o3"]), None);
+    }
+}
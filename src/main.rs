@@ -1,17 +1,57 @@
 use clap::Parser;
-use daberu::ChatGpt;
-use orfail::OrFail;
+use daberu::{ChatGpt, DaberuError, Interrupt};
+use std::{process::ExitCode, sync::Arc};
 
-/// ChatGPT client tool that reads your message from stdin and writes the response to stdout.
+/// ChatGPT client tool that sends your message (`MESSAGE`, `--message` or stdin) and writes the response to stdout.
 #[derive(Debug, Parser)]
-#[command(version)]
+#[command(
+    version,
+    after_help = "Exit codes:
+  0   Success
+  1   Generic failure
+  2   Usage error (invalid command-line arguments or input message)
+  4   Authentication failure (HTTP 401 or 403)
+  8   Rate limit exceeded (HTTP 429)
+  16  Server error (HTTP 5xx or 529)
+  130 Interrupted by Ctrl-C (a partially streamed reply is saved to --log)"
+)]
 struct Args {
     #[clap(flatten)]
     chatgpt: ChatGpt,
 }
 
-fn main() -> orfail::Result<()> {
-    let args = Args::parse();
-    args.chatgpt.call().or_fail()?;
-    Ok(())
+fn main() -> ExitCode {
+    let mut args = Args::parse();
+
+    // While a reply is streamed, the first Ctrl-C stops it (and it is saved to `--log`).
+    // Otherwise, Ctrl-C exits at once.
+    let interrupt = Arc::new(Interrupt::default());
+    args.chatgpt.set_interrupt(Arc::clone(&interrupt));
+    if let Err(e) = ctrlc::set_handler(move || {
+        if !interrupt.request() {
+            std::process::exit(130);
+        }
+    }) {
+        eprintln!("warning: failed to set the Ctrl-C handler ({e})");
+    }
+
+    match args.chatgpt.call() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+fn exit_code(e: &DaberuError) -> u8 {
+    match e {
+        DaberuError::Usage { .. } => 2,
+        DaberuError::Auth { .. } => 4,
+        DaberuError::RateLimited { .. } => 8,
+        DaberuError::Overloaded { .. } => 16,
+        DaberuError::Http { status, .. } if *status >= 500 => 16,
+        DaberuError::Interrupted => 130,
+        _ => 1,
+    }
 }
@@ -0,0 +1,58 @@
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Elapsed-time indicator shown on stderr while waiting for the reply. It is cleared when dropped.
+#[derive(Debug)]
+pub struct Spinner {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub fn start() -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = std::thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                let start = Instant::now();
+                let mut stderr = std::io::stderr();
+                let mut frame = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let elapsed = start.elapsed().as_secs_f64();
+                    let _ = write!(
+                        stderr,
+                        "\r{} waiting for reply {elapsed:.1}s",
+                        FRAMES[frame]
+                    );
+                    let _ = stderr.flush();
+                    frame = (frame + 1) % FRAMES.len();
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                let _ = write!(stderr, "\r\x1b[K");
+                let _ = stderr.flush();
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}